@@ -12,7 +12,10 @@
 // See the License for the specific language governing permissions and
 // limitations under the License.
 
-use std::{collections::HashMap, collections::HashSet};
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap, HashSet},
+};
 
 use crate::{
     byvalue_checker::ByValueChecker,
@@ -25,7 +28,10 @@ use crate::{
 };
 use proc_macro2::TokenStream as TokenStream2;
 use quote::quote;
-use syn::{parse_quote, Fields, ForeignItem, Item, ItemStruct, Type};
+use syn::{
+    parse_quote, BareFnArg, Expr, ExprLit, Field, Fields, ForeignItem, Ident, ImplItem, Item,
+    ItemImpl, ItemStruct, Lit, ReturnType, Type,
+};
 
 use super::{
     super::{
@@ -47,6 +53,26 @@ enum TypeKind {
     ForwardDeclaration, // no full C++ declaration available - can't even generate UniquePtr
 }
 
+/// Standard traits we'll attempt to auto-derive on POD (`cxx::kind::Trivial`)
+/// structs and enums. A type can derive a given trait iff every field (or,
+/// for enums, every variant's payload) can also derive it; see
+/// `ParseBindgen::compute_derivable_traits`.
+const DERIVABLE_TRAITS: &[&str] = &["Debug", "PartialEq", "Eq", "Hash", "Default", "Clone", "Copy"];
+
+/// The length beyond which `std` stops providing blanket trait impls for
+/// fixed-size arrays, and beyond which we therefore refuse to derive
+/// anything for a struct embedding such an array by value.
+const MAX_DERIVABLE_ARRAY_LEN: u64 = 32;
+
+/// A single entry in a C++ vtable, translated into the shape of a Rust
+/// trait method: the `this` pointer from the original bindgen
+/// function-pointer field has already been stripped off.
+struct VirtualMethodSig {
+    name: Ident,
+    inputs: Vec<BareFnArg>,
+    output: ReturnType,
+}
+
 /// Parses a bindgen mod in order to understand the APIs within it.
 pub(crate) struct ParseBindgen<'a> {
     type_converter: TypeConverter<'a>,
@@ -57,6 +83,52 @@ pub(crate) struct ParseBindgen<'a> {
     incomplete_types: HashSet<TypeName>,
     results: ParseResults,
     unsafe_policy: UnsafePolicy,
+    /// Direct field/dependency types of each POD struct or enum we've seen,
+    /// keyed by the type's own name. Used by `compute_derivable_traits` to
+    /// work out which standard traits can be auto-derived.
+    pod_field_types: HashMap<TypeName, HashSet<TypeName>>,
+    /// Types which can never derive any standard trait: `Opaque` types,
+    /// forward declarations, and POD types which embed something we can't
+    /// derive through (e.g. `UniquePtr`, `CxxString`, or an oversized array).
+    non_derivable_types: HashSet<TypeName>,
+    /// bindgen's `<Class>__bindgen_vtable` structs, keyed by the owning
+    /// class's name, gathered by `prescan_vtable_structs` before we process
+    /// the rest of a mod's items. These hold one function-pointer field per
+    /// virtual method and are how we detect (and describe) abstract classes.
+    vtable_structs: HashMap<TypeName, ItemStruct>,
+    /// Classes bindgen has emitted at least one constructor binding for,
+    /// gathered by `prescan_constructible_types`. C++ won't let you `new`
+    /// an abstract class, so bindgen never binds a constructor for one;
+    /// finding one here is therefore real evidence a `__bindgen_vtable`-
+    /// shaped struct is actually concrete, not abstract.
+    constructible_types: HashSet<TypeName>,
+    /// POD structs which contain a bindgen bitfield-unit storage field,
+    /// so that when we later see their accessor `impl` block we know to
+    /// retain its getter/setter methods rather than discard them.
+    bitfield_structs: HashSet<TypeName>,
+    /// The bitfield storage field name(s) (e.g. `_bitfield_1`) of each
+    /// entry in `bitfield_structs`, so a method in that struct's `impl`
+    /// block can be confirmed as an actual bitfield accessor - by
+    /// reference to one of these fields - rather than assumed to be one
+    /// purely from its `&self`-only shape, which an ordinary zero-arg
+    /// method sharing the same impl block could also have.
+    bitfield_field_names: HashMap<TypeName, HashSet<String>>,
+    /// Bitfield getter/setter/constructor methods recovered from `impl`
+    /// blocks, keyed by the struct they belong to, pending being spliced
+    /// into that struct's `Api::impl_entry` by `attach_bitfield_accessors`.
+    bitfield_accessors: HashMap<TypeName, Vec<ImplItem>>,
+    /// Enums for which bindgen flattened their variants into top-level
+    /// `pub const Enum_Variant: Enum = ...;` items, mirroring C++'s
+    /// unqualified lookup of classic (non-`enum class`) enum variants.
+    /// Populated by `prescan_unscoped_enums`; absence means `enum class`.
+    unscoped_enums: HashSet<TypeName>,
+    /// Types which began life as a C++ enum and were rewritten by
+    /// `process_enum` into a `#[repr(transparent)]` newtype wrapping the
+    /// underlying integer. A default-constructed newtype's `0` value isn't
+    /// guaranteed to match any of the enum's variants, so `Default` must
+    /// still be withheld from these even though `bindgen_mod_item` now
+    /// holds a plain `Item::Struct` rather than `Item::Enum`.
+    enum_types: HashSet<TypeName>,
 }
 
 impl<'a> ParseBindgen<'a> {
@@ -77,6 +149,15 @@ impl<'a> ParseBindgen<'a> {
                 use_stmts_by_mod: HashMap::new(),
             },
             unsafe_policy,
+            pod_field_types: HashMap::new(),
+            non_derivable_types: HashSet::new(),
+            vtable_structs: HashMap::new(),
+            constructible_types: HashSet::new(),
+            bitfield_structs: HashSet::new(),
+            bitfield_field_names: HashMap::new(),
+            bitfield_accessors: HashMap::new(),
+            unscoped_enums: HashSet::new(),
+            enum_types: HashSet::new(),
         }
     }
 
@@ -104,6 +185,9 @@ impl<'a> ParseBindgen<'a> {
         }
         let root_ns = Namespace::new();
         self.convert_mod_items(items, root_ns)?;
+        self.apply_derived_traits();
+        self.attach_bitfield_accessors();
+        self.consolidate_and_order_apis();
         Ok(self.results)
     }
 
@@ -114,6 +198,9 @@ impl<'a> ParseBindgen<'a> {
         // this particular mod.
         let mut mod_converter = ParseForeignMod::new(ns.clone());
         let mut use_statements_for_this_mod = Vec::new();
+        self.prescan_vtable_structs(&items, &ns);
+        self.prescan_constructible_types(&items, &ns);
+        self.prescan_unscoped_enums(&items, &ns);
         for item in items {
             match item {
                 Item::ForeignMod(mut fm) => {
@@ -121,8 +208,48 @@ impl<'a> ParseBindgen<'a> {
                     fm.items = Vec::new();
                     mod_converter.convert_foreign_mod_items(items)?;
                 }
+                Item::Struct(s) if Self::is_vtable_struct(&s) => {
+                    // Internal bindgen bookkeeping for virtual dispatch, not
+                    // a type we expose; already captured by the prescan.
+                }
                 Item::Struct(mut s) => {
                     let tyname = TypeName::new(&ns, &s.ident.to_string());
+                    // A `__bindgen_vtable`-shaped, storage-less struct is
+                    // emitted the same way for an abstract class and for a
+                    // concrete one that simply has no data members of its
+                    // own, so that shape alone can't tell them apart. Only
+                    // take the trampoline path when `prescan_constructible_types`
+                    // also found no bindgen constructor for it - C++ won't
+                    // let you `new` an abstract class, so bindgen can't
+                    // bind one either, making its absence real evidence of
+                    // abstractness rather than a guess from field count.
+                    if s.fields.len() <= 1 && !self.constructible_types.contains(&tyname) {
+                        if let Some(vtable) = self.vtable_structs.get(&tyname).cloned() {
+                            // Abstract and storage-less: it can never be
+                            // held by value or derive anything, only ever
+                            // reached via `UniquePtr`, so declare it the
+                            // same way any other `Opaque` type is declared
+                            // - `generate_type` registers it with the type
+                            // converter too.
+                            self.non_derivable_types.insert(tyname.clone());
+                            make_non_pod(&mut s);
+                            let emitted = self.generate_type(
+                                tyname.clone(),
+                                TypeKind::NonPOD,
+                                HashSet::new(),
+                                Some(Item::Struct(s)),
+                                None,
+                            );
+                            // If the type itself was blocklisted, nothing
+                            // was declared for it in the bridge, so a
+                            // trampoline referring to it would fail to
+                            // compile - skip it too.
+                            if emitted {
+                                self.generate_vtable_trampoline(&ns, tyname, &vtable);
+                            }
+                            continue;
+                        }
+                    }
                     let type_kind = if Self::spot_forward_declaration(&s.fields) {
                         self.incomplete_types.insert(tyname.clone());
                         TypeKind::ForwardDeclaration
@@ -134,19 +261,41 @@ impl<'a> ParseBindgen<'a> {
                     // We either leave a bindgen struct untouched, or we completely
                     // replace its contents with opaque nonsense.
                     let field_types = match type_kind {
-                        TypeKind::POD => self.get_struct_field_types(&ns, &s)?,
+                        TypeKind::POD => {
+                            let field_types = self.get_struct_field_types(&ns, &s)?;
+                            if Self::struct_has_hard_derive_blocker(&s.fields) {
+                                self.non_derivable_types.insert(tyname.clone());
+                            }
+                            let bitfield_names = Self::bitfield_field_idents(&s.fields);
+                            if !bitfield_names.is_empty() {
+                                self.bitfield_structs.insert(tyname.clone());
+                                self.bitfield_field_names.insert(tyname.clone(), bitfield_names);
+                            }
+                            self.pod_field_types.insert(tyname.clone(), field_types.clone());
+                            field_types
+                        }
                         _ => {
+                            self.non_derivable_types.insert(tyname.clone());
                             make_non_pod(&mut s);
                             HashSet::new()
                         }
                     };
                     // cxx::bridge can't cope with type aliases to generic
                     // types at the moment.
-                    self.generate_type(tyname, type_kind, field_types, Some(Item::Struct(s)));
+                    self.generate_type(tyname, type_kind, field_types, Some(Item::Struct(s)), None);
                 }
                 Item::Enum(e) => {
                     let tyname = TypeName::new(&ns, &e.ident.to_string());
-                    self.generate_type(tyname, TypeKind::POD, HashSet::new(), Some(Item::Enum(e)));
+                    self.pod_field_types.insert(tyname.clone(), HashSet::new());
+                    self.enum_types.insert(tyname.clone());
+                    let (s, consts_impl) = self.process_enum(e, &tyname);
+                    self.generate_type(
+                        tyname,
+                        TypeKind::POD,
+                        HashSet::new(),
+                        Some(Item::Struct(s)),
+                        Some(consts_impl),
+                    );
                 }
                 Item::Impl(imp) => {
                     // We *mostly* ignore all impl blocks generated by bindgen.
@@ -156,6 +305,18 @@ impl<'a> ParseBindgen<'a> {
                     // We do however record which methods were spotted, since
                     // we have no other way of working out which functions are
                     // static methods vs plain functions.
+                    //
+                    // One exception: bindgen's bitfield accessor methods
+                    // (getters named after the field, set_*, and
+                    // new_bitfield_*) have no counterpart in
+                    // any 'extern "C"' block, so if this impl belongs to a
+                    // struct with bitfield storage we keep hold of them to
+                    // splice back in later.
+                    if let Some(tyname) = Self::impl_target_tyname(&imp, &ns) {
+                        if self.bitfield_structs.contains(&tyname) {
+                            self.record_bitfield_accessors(tyname, &imp);
+                        }
+                    }
                     mod_converter.convert_impl_items(imp);
                 }
                 Item::Mod(itm) => {
@@ -242,6 +403,13 @@ impl<'a> ParseBindgen<'a> {
     ) -> Result<HashSet<TypeName>, ConvertError> {
         let mut results = HashSet::new();
         for f in &s.fields {
+            if Self::is_bitfield_storage_field(f) {
+                // Bindgen's bitfield-unit storage: an opaque run of bytes
+                // that preserves layout but carries no type dependency of
+                // its own. Its accessors are recovered separately, in
+                // `record_bitfield_accessors`.
+                continue;
+            }
             let annotated = self.type_converter.convert_type(f.ty.clone(), ns)?;
             self.results.apis.extend(annotated.extra_apis);
             results.extend(annotated.types_encountered);
@@ -249,25 +417,856 @@ impl<'a> ParseBindgen<'a> {
         Ok(results)
     }
 
+    /// The name(s) of a struct's bitfield-unit storage field(s), if any.
+    fn bitfield_field_idents(fields: &Fields) -> HashSet<String> {
+        fields
+            .iter()
+            .filter(|f| Self::is_bitfield_storage_field(f))
+            .filter_map(|f| f.ident.as_ref().map(|id| id.to_string()))
+            .collect()
+    }
+
+    fn is_bitfield_storage_field(f: &Field) -> bool {
+        f.ident
+            .as_ref()
+            .map_or(false, |id| id.to_string().starts_with("_bitfield_"))
+            || matches!(&f.ty, Type::Path(tp) if tp.path.segments.iter().any(|seg| seg.ident == "__BindgenBitfieldUnit"))
+    }
+
+    fn impl_target_tyname(imp: &ItemImpl, ns: &Namespace) -> Option<TypeName> {
+        match imp.self_ty.as_ref() {
+            Type::Path(tp) => tp
+                .path
+                .segments
+                .last()
+                .map(|seg| TypeName::new(ns, &seg.ident.to_string())),
+            _ => None,
+        }
+    }
+
+    /// bindgen names a bitfield setter `set_<field>` and a bitfield-unit
+    /// constructor `new_bitfield_<n>`, but a getter is simply named after
+    /// the field itself with no prefix at all, with the same `&self`-only
+    /// shape as any other zero-arg const accessor method bindgen might
+    /// have put in the same `impl` block - so a getter can only be told
+    /// apart from an unrelated real method by checking that its body
+    /// actually touches one of the struct's bitfield storage fields.
+    fn is_bitfield_accessor(m: &syn::ImplItemMethod, bitfield_field_names: &HashSet<String>) -> bool {
+        let name = m.sig.ident.to_string();
+        if name.starts_with("set_") || name.starts_with("new_bitfield_") {
+            return true;
+        }
+        let is_self_only =
+            m.sig.inputs.len() == 1 && matches!(m.sig.inputs.first(), Some(syn::FnArg::Receiver(_)));
+        if !is_self_only {
+            return false;
+        }
+        let body = quote!(#m).to_string();
+        bitfield_field_names
+            .iter()
+            .any(|field| body.contains(field.as_str()))
+    }
+
+    fn record_bitfield_accessors(&mut self, tyname: TypeName, imp: &ItemImpl) {
+        let bitfield_field_names = match self.bitfield_field_names.get(&tyname) {
+            Some(names) => names,
+            None => return,
+        };
+        let methods: Vec<ImplItem> = imp
+            .items
+            .iter()
+            .filter(|item| {
+                matches!(item, ImplItem::Method(m) if Self::is_bitfield_accessor(m, bitfield_field_names))
+            })
+            .cloned()
+            .collect();
+        if !methods.is_empty() {
+            self.bitfield_accessors
+                .entry(tyname)
+                .or_default()
+                .extend(methods);
+        }
+    }
+
+    /// Splices any bitfield accessor methods recovered from bindgen's
+    /// `impl` blocks into the `impl_entry` of the struct's `Api`, so Rust
+    /// callers can still read and write individual bitfields. Must run
+    /// after `convert_mod_items` has finished, since a struct's `impl`
+    /// block is processed after the struct's own `Api` already exists.
+    fn attach_bitfield_accessors(&mut self) {
+        for api in &mut self.results.apis {
+            let tyname = TypeName::new(&api.ns, &api.id.to_string());
+            if let Some(methods) = self.bitfield_accessors.remove(&tyname) {
+                let ident = &api.id;
+                api.impl_entry = Some(Item::Impl(parse_quote! {
+                    impl #ident {
+                        #(#methods)*
+                    }
+                }));
+            }
+        }
+    }
+
+    /// Does this struct have a field which rules out deriving any standard
+    /// trait at all, regardless of what its other fields look like? This
+    /// covers cases the fixed-point analysis in `compute_derivable_traits`
+    /// can't see because the field type never gets its own `Api`: bridge
+    /// types held by value, and arrays too long for `std` to derive over.
+    fn struct_has_hard_derive_blocker(fields: &Fields) -> bool {
+        fields.iter().any(|f| Self::type_blocks_derive(&f.ty))
+    }
+
+    fn type_blocks_derive(ty: &Type) -> bool {
+        match ty {
+            Type::Path(tp) => tp
+                .path
+                .segments
+                .iter()
+                .any(|seg| seg.ident == "UniquePtr" || seg.ident == "CxxString"),
+            Type::Array(arr) => matches!(
+                &arr.len,
+                Expr::Lit(ExprLit { lit: Lit::Int(n), .. })
+                    if n.base10_parse::<u64>().map_or(false, |len| len > MAX_DERIVABLE_ARRAY_LEN)
+            ),
+            _ => false,
+        }
+    }
+
+    /// Fixed-point analysis over the POD type-dependency graph gathered
+    /// while walking the bindgen mod: a POD struct or enum can derive a
+    /// given trait iff every field type can derive it too. We start
+    /// assuming nothing is derivable and grow the derivable sets until
+    /// they stop changing, so mutually-referential or not-yet-proven
+    /// types are conservatively treated as non-derivable rather than
+    /// looping forever.
+    fn compute_derivable_traits(&self) -> HashMap<TypeName, HashSet<&'static str>> {
+        let mut derivable: HashMap<TypeName, HashSet<&'static str>> = HashMap::new();
+        loop {
+            let mut changed = false;
+            for (tyname, fields) in &self.pod_field_types {
+                if self.non_derivable_types.contains(tyname) {
+                    continue;
+                }
+                let current = derivable.entry(tyname.clone()).or_default();
+                for &trait_name in DERIVABLE_TRAITS {
+                    if current.contains(trait_name) {
+                        continue;
+                    }
+                    let all_fields_derive = fields.iter().all(|field_ty| {
+                        if self.non_derivable_types.contains(field_ty) {
+                            false
+                        } else {
+                            match self.pod_field_types.get(field_ty) {
+                                // A field which is itself one of our POD types:
+                                // only derivable once we've proven it so.
+                                Some(_) => derivable
+                                    .get(field_ty)
+                                    .map_or(false, |s| s.contains(trait_name)),
+                                // Not a type we generated: a primitive or
+                                // other builtin, seeded as derivable except
+                                // where we know better (e.g. floats don't
+                                // implement Eq/Hash).
+                                None => !Self::primitive_blocks_trait(field_ty, trait_name),
+                            }
+                        }
+                    });
+                    if all_fields_derive {
+                        current.insert(trait_name);
+                        changed = true;
+                    }
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+        derivable
+    }
+
+    /// `f32`/`f64` are the only primitives among `DERIVABLE_TRAITS`' traits
+    /// that don't support every one of them (no `Eq`, no `Hash`), so a
+    /// field of an untracked builtin type needs this check rather than
+    /// being seeded as unconditionally derivable.
+    fn primitive_blocks_trait(tyname: &TypeName, trait_name: &str) -> bool {
+        if tyname.has_namespace() {
+            return false;
+        }
+        let is_float = matches!(tyname.get_final_ident(), "f32" | "f64");
+        is_float && matches!(trait_name, "Eq" | "Hash")
+    }
+
+    /// Builds the `#[derive(...)]` attribute for a set of derivable traits,
+    /// or `None` if nothing can be derived. Trait names are emitted in the
+    /// fixed `DERIVABLE_TRAITS` order for deterministic output. `Default`
+    /// is withheld from `is_enum_newtype` types: `process_enum` represents
+    /// a C++ enum as a `#[repr(transparent)]` newtype over its underlying
+    /// integer, and a default-constructed `0` isn't guaranteed to match any
+    /// of the enum's variants, so we never claim one of these can derive it.
+    fn derive_attr_for(traits: &HashSet<&'static str>, is_enum_newtype: bool) -> Option<syn::Attribute> {
+        let idents: Vec<_> = DERIVABLE_TRAITS
+            .iter()
+            .filter(|t| traits.contains(*t))
+            .filter(|t| !is_enum_newtype || **t != "Default")
+            .map(|t| make_ident(t))
+            .collect();
+        if idents.is_empty() {
+            return None;
+        }
+        Some(parse_quote! { #[derive(#(#idents),*)] })
+    }
+
+    /// Runs the derivable-traits analysis and splices the resulting
+    /// `#[derive(...)]` attribute onto each POD struct's (including the
+    /// enum newtypes produced by `process_enum`) `bindgen_mod_item`. Must
+    /// run after `convert_mod_items` has finished, since the analysis needs
+    /// the full dependency graph up front.
+    fn apply_derived_traits(&mut self) {
+        let derivable = self.compute_derivable_traits();
+        for api in &mut self.results.apis {
+            let tyname = TypeName::new(&api.ns, &api.id.to_string());
+            let traits = match derivable.get(&tyname) {
+                Some(traits) if !traits.is_empty() => traits,
+                _ => continue,
+            };
+            let is_enum_newtype = self.enum_types.contains(&tyname);
+            let attr = match Self::derive_attr_for(traits, is_enum_newtype) {
+                Some(attr) => attr,
+                None => continue,
+            };
+            if let Some(Item::Struct(s)) = &mut api.bindgen_mod_item {
+                s.attrs.push(attr);
+            }
+        }
+    }
+
+    /// Final tidy-up pass over the whole set of `Api`s, run once
+    /// everything else has been gathered: drop any `Api` with no output at
+    /// all, then bucket the survivors by namespace - preserving the order in
+    /// which each namespace was first encountered, since that already
+    /// respects any cross-namespace dependency (bindgen only emits a type
+    /// after the namespaces it depends on) and a topo sort here only has
+    /// visibility into each bucket's own `deps` - and topologically sort
+    /// each bucket so a type always precedes anything depending on it,
+    /// breaking ties by identifier name. Finally, coalesce each bucket's
+    /// `extern_c_mod_item` fragments - which all share the same namespace
+    /// attribute by construction - into one, so we don't scatter a
+    /// separate single-item `extern "C++"` block per `Api` in the
+    /// generated bridge.
+    fn consolidate_and_order_apis(&mut self) {
+        self.gc_unused_apis();
+        let mut buckets: HashMap<String, Vec<Api>> = HashMap::new();
+        let mut ns_keys: Vec<String> = Vec::new();
+        for api in std::mem::take(&mut self.results.apis) {
+            let tyname = TypeName::new(&api.ns, &api.id.to_string());
+            let ns_key = tyname
+                .ns_segment_iter()
+                .cloned()
+                .collect::<Vec<_>>()
+                .join("::");
+            if !buckets.contains_key(&ns_key) {
+                ns_keys.push(ns_key.clone());
+            }
+            buckets.entry(ns_key).or_default().push(api);
+        }
+        let mut ordered = Vec::new();
+        for ns_key in ns_keys {
+            let mut bucket = Self::topo_sort_bucket(buckets.remove(&ns_key).unwrap());
+            Self::coalesce_extern_c_items(&mut bucket);
+            ordered.extend(bucket);
+        }
+        self.results.apis = ordered;
+    }
+
+    /// Drops any `Api` that would emit nothing at all, so a stray
+    /// bookkeeping entry can never turn into an empty coalesced block
+    /// below.
+    fn gc_unused_apis(&mut self) {
+        self.results.apis.retain(Self::api_has_output);
+    }
+
+    fn api_has_output(api: &Api) -> bool {
+        api.bindgen_mod_item.is_some()
+            || api.bridge_item.is_some()
+            || api.extern_c_mod_item.is_some()
+            || !api.global_items.is_empty()
+            || api.additional_cpp.is_some()
+            || api.impl_entry.is_some()
+    }
+
+    /// Kahn's algorithm over one namespace bucket's `deps`, restricted to
+    /// edges within the bucket (a dependency in another namespace is
+    /// assumed already emitted by the time this bucket's mod is reached).
+    /// Ties among ready nodes are broken by identifier name so the output
+    /// order is stable across runs.
+    fn topo_sort_bucket(apis: Vec<Api>) -> Vec<Api> {
+        let by_identity: HashMap<TypeName, usize> = apis
+            .iter()
+            .enumerate()
+            .map(|(i, api)| (TypeName::new(&api.ns, &api.id.to_string()), i))
+            .collect();
+        let mut dependents: Vec<Vec<usize>> = vec![Vec::new(); apis.len()];
+        let mut in_degree = vec![0usize; apis.len()];
+        for (i, api) in apis.iter().enumerate() {
+            for dep in &api.deps {
+                if let Some(&dep_idx) = by_identity.get(dep) {
+                    if dep_idx != i {
+                        dependents[dep_idx].push(i);
+                        in_degree[i] += 1;
+                    }
+                }
+            }
+        }
+        let mut ready: BinaryHeap<Reverse<(String, usize)>> = apis
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| in_degree[*i] == 0)
+            .map(|(i, api)| Reverse((api.id.to_string(), i)))
+            .collect();
+        let mut order = Vec::with_capacity(apis.len());
+        while let Some(Reverse((_, i))) = ready.pop() {
+            order.push(i);
+            for &next in &dependents[i] {
+                in_degree[next] -= 1;
+                if in_degree[next] == 0 {
+                    ready.push(Reverse((apis[next].id.to_string(), next)));
+                }
+            }
+        }
+        if order.len() < apis.len() {
+            // A dependency cycle: append whatever's left in name order
+            // rather than silently dropping it.
+            let seen: HashSet<usize> = order.iter().cloned().collect();
+            let mut remaining: Vec<usize> = (0..apis.len()).filter(|i| !seen.contains(i)).collect();
+            remaining.sort_by_key(|&i| apis[i].id.to_string());
+            order.extend(remaining);
+        }
+        let mut slots: Vec<Option<Api>> = apis.into_iter().map(Some).collect();
+        order
+            .into_iter()
+            .map(|i| slots[i].take().unwrap())
+            .collect()
+    }
+
+    /// Merges the `ForeignItem::Verbatim` fragments of every `Api` in this
+    /// bucket - which, because the bucket is keyed by namespace, all carry
+    /// the same `#[namespace = ...]` attribute - into a single fragment
+    /// carried by the first `Api` that had one, clearing the rest. Only
+    /// `ForeignItem::Verbatim` entries are touched: anything else (e.g. a
+    /// `ForeignItem::Fn` for an actual function declaration) is left in
+    /// place untouched.
+    fn coalesce_extern_c_items(apis: &mut [Api]) {
+        let mut combined: Option<TokenStream2> = None;
+        let mut first_idx = None;
+        for i in 0..apis.len() {
+            if !matches!(apis[i].extern_c_mod_item, Some(ForeignItem::Verbatim(_))) {
+                continue;
+            }
+            if let Some(ForeignItem::Verbatim(ts)) = apis[i].extern_c_mod_item.take() {
+                match &mut combined {
+                    Some(acc) => acc.extend(ts),
+                    None => {
+                        combined = Some(ts);
+                        first_idx = Some(i);
+                    }
+                }
+            }
+        }
+        if let (Some(ts), Some(idx)) = (combined, first_idx) {
+            apis[idx].extern_c_mod_item = Some(ForeignItem::Verbatim(ts));
+        }
+    }
+
+    /// Scans the raw items of a mod for bindgen's `<Class>__bindgen_vtable`
+    /// structs (emitted for any class with virtual methods) so that, when
+    /// we later encounter the class itself, we already know its virtual
+    /// method signatures.
+    fn prescan_vtable_structs(&mut self, items: &[Item], ns: &Namespace) {
+        for item in items {
+            if let Item::Struct(s) = item {
+                if let Some(owner) = s.ident.to_string().strip_suffix("__bindgen_vtable") {
+                    let tyname = TypeName::new(ns, owner);
+                    self.vtable_structs.insert(tyname, s.clone());
+                }
+            }
+        }
+    }
+
+    /// Scans the raw items of a mod for bindgen's constructor bindings:
+    /// `extern "C"` functions named `<Class>_<Class>...` whose first
+    /// parameter is `this: *mut <Class>`. bindgen only ever binds these for
+    /// types C++ permits `new`-ing, so finding one is a real signal that a
+    /// class is concrete, unlike the mere shape of its `__bindgen_vtable`
+    /// struct, which looks the same whether the class is abstract or not.
+    fn prescan_constructible_types(&mut self, items: &[Item], ns: &Namespace) {
+        for item in items {
+            if let Item::ForeignMod(fm) = item {
+                for fm_item in &fm.items {
+                    if let ForeignItem::Fn(f) = fm_item {
+                        if let Some(tyname) = Self::constructor_owner(&f.sig, ns) {
+                            self.constructible_types.insert(tyname);
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// If `sig` looks like a bindgen constructor binding (its first
+    /// parameter is `this: *mut SomeClass`, and it's named `Class_Class`
+    /// with an optional numeric overload suffix), returns the class it
+    /// constructs. Every class with a virtual destructor - abstract or not
+    /// - also gets a `Class_Class_destructor` binding from bindgen, so the
+    /// suffix must be checked to be purely numeric or absent rather than
+    /// just any string starting with the right prefix, or that destructor
+    /// binding would be mistaken for a constructor.
+    fn constructor_owner(sig: &syn::Signature, ns: &Namespace) -> Option<TypeName> {
+        let this_ty = match sig.inputs.first()? {
+            syn::FnArg::Typed(pat_ty) => match pat_ty.ty.as_ref() {
+                Type::Ptr(p) => p.elem.as_ref(),
+                _ => return None,
+            },
+            _ => return None,
+        };
+        let ident = match this_ty {
+            Type::Path(tp) => tp.path.segments.last()?.ident.to_string(),
+            _ => return None,
+        };
+        let fn_name = sig.ident.to_string();
+        let ctor_prefix = format!("{}_{}", ident, ident);
+        let suffix = fn_name.strip_prefix(&ctor_prefix)?;
+        if suffix.is_empty() || suffix.chars().all(|c| c.is_ascii_digit()) {
+            Some(TypeName::new(ns, &ident))
+        } else {
+            None
+        }
+    }
+
+    /// Scans the raw items of a mod for top-level constants whose type is
+    /// one of its enums: bindgen emits these to mirror the unqualified
+    /// visibility that classic (non-`enum class`) enum variants have in
+    /// C++, so their presence tells us the enum is unscoped.
+    fn prescan_unscoped_enums(&mut self, items: &[Item], ns: &Namespace) {
+        for item in items {
+            if let Item::Const(c) = item {
+                if let Type::Path(tp) = c.ty.as_ref() {
+                    if let Some(seg) = tp.path.segments.last() {
+                        self.unscoped_enums
+                            .insert(TypeName::new(ns, &seg.ident.to_string()));
+                    }
+                }
+            }
+        }
+    }
+
+    /// Reads the underlying integer type bindgen already worked out for
+    /// this enum from its `#[repr(...)]` attribute, if any.
+    fn enum_repr_type(e: &syn::ItemEnum) -> Option<Ident> {
+        e.attrs.iter().find_map(|attr| {
+            if attr.path.is_ident("repr") {
+                attr.parse_args::<Ident>().ok()
+            } else {
+                None
+            }
+        })
+    }
+
+    /// C++ permits any value of an enum's underlying type to cross the ABI
+    /// boundary regardless of which variants are listed, but constructing
+    /// a genuine Rust `enum` from a discriminant that isn't one of its
+    /// listed variants is immediate undefined behaviour - `#[non_exhaustive]`
+    /// doesn't change this, since it's purely a cross-crate lint with no
+    /// effect inside the crate that defines the enum. So instead of a real
+    /// `enum`, we emit a `#[repr(transparent)]` newtype wrapping the
+    /// underlying integer, with one associated `const` per variant: any
+    /// value of the wrapped integer is valid, so there's no invalid state
+    /// to receive from C++ in the first place. Variant discriminants are
+    /// left exactly as bindgen wrote them.
+    fn process_enum(&self, e: syn::ItemEnum, tyname: &TypeName) -> (ItemStruct, Item) {
+        let is_unscoped = self.unscoped_enums.contains(tyname);
+        // A classic (unscoped) enum with no explicit underlying type
+        // defaults to `int` in C++; bindgen always gives `enum class` an
+        // explicit, already-correct `#[repr(...)]`.
+        let repr_ty = Self::enum_repr_type(&e).unwrap_or_else(|| make_ident("i32"));
+        let ident = &e.ident;
+        let mut next_discriminant: i128 = 0;
+        let consts: Vec<TokenStream2> = e
+            .variants
+            .iter()
+            .map(|v| {
+                let vident = &v.ident;
+                let value_ts = match &v.discriminant {
+                    Some((_, Expr::Lit(ExprLit { lit: Lit::Int(n), .. }))) => {
+                        if let Ok(parsed) = n.base10_parse::<i128>() {
+                            next_discriminant = parsed + 1;
+                        }
+                        quote! { #n }
+                    }
+                    Some((_, expr)) => quote! { #expr },
+                    None => {
+                        let lit =
+                            syn::LitInt::new(&next_discriminant.to_string(), vident.span());
+                        next_discriminant += 1;
+                        quote! { #lit }
+                    }
+                };
+                quote! { pub const #vident: #ident = #ident(#value_ts as #repr_ty); }
+            })
+            .collect();
+        let mut s: ItemStruct = parse_quote! {
+            #[repr(transparent)]
+            pub struct #ident(pub #repr_ty);
+        };
+        if is_unscoped {
+            // Classic C enums implicitly convert to their underlying
+            // integer type and so support ordering comparisons;
+            // `enum class` doesn't get these for free in C++ either.
+            s.attrs.push(parse_quote! { #[derive(PartialOrd, Ord)] });
+        }
+        let consts_impl: Item = parse_quote! {
+            impl #ident {
+                #(#consts)*
+            }
+        };
+        (s, consts_impl)
+    }
+
+    fn is_vtable_struct(s: &ItemStruct) -> bool {
+        s.ident.to_string().ends_with("__bindgen_vtable")
+    }
+
+    fn extract_virtual_methods(owner: &str, vtable: &ItemStruct) -> Vec<VirtualMethodSig> {
+        let prefix = format!("{}_", owner);
+        vtable
+            .fields
+            .iter()
+            .filter_map(|f| {
+                let field_ident = f.ident.as_ref()?.to_string();
+                let bare_fn = match &f.ty {
+                    Type::BareFn(b) => b,
+                    _ => return None,
+                };
+                // The destructor slot is handled by the shim's own
+                // destructor, not by a trait method.
+                if field_ident.contains("destructor") {
+                    return None;
+                }
+                let method_name = field_ident
+                    .strip_prefix(&prefix)
+                    .unwrap_or(&field_ident)
+                    .to_string();
+                Some(VirtualMethodSig {
+                    name: make_ident(&method_name),
+                    // Drop the leading `this: *mut c_void` argument; it's
+                    // implicit in the trait method's `&self`.
+                    inputs: bare_fn.inputs.iter().skip(1).cloned().collect(),
+                    output: bare_fn.output.clone(),
+                })
+            })
+            .collect()
+    }
+
+    /// When bindgen shows us a vtable-only class (no storage of its own,
+    /// just a pointer to its `__bindgen_vtable`), the caller declares it as
+    /// an ordinary `Opaque` type, but that alone gives Rust no way to make
+    /// one: it's abstract, so there's nothing to hold by value and no C++
+    /// constructor to call. So in addition we generate:
+    ///  - a Rust trait mirroring its virtual methods,
+    ///  - a `Box<dyn Trait>`-boxing free function Rust code can call to
+    ///    hand an implementation to C++,
+    ///  - `extern "C"` thunks which dispatch from C++ back into the boxed
+    ///    trait object,
+    ///  - a C++ shim subclass (in `additional_cpp`) which overrides each
+    ///    virtual method to call the matching thunk, storing the boxed
+    ///    Rust object as an opaque `void*` and freeing it via a drop thunk
+    ///    in its own destructor,
+    ///  - a bridged `extern "C++"` constructor function, so Rust code can
+    ///    actually obtain a `UniquePtr` to the shim (upcast to the abstract
+    ///    base class) rather than the trampoline machinery sitting unreachable.
+    fn generate_vtable_trampoline(&mut self, ns: &Namespace, tyname: TypeName, vtable: &ItemStruct) {
+        let owner = tyname.get_final_ident().to_string();
+        let owner_ident = make_ident(&owner);
+        let methods = Self::extract_virtual_methods(&owner, vtable);
+        let trait_ident = make_ident(&format!("{}Trait", owner));
+        let shim_ident = format!("{}Rs", owner);
+        let box_fn_ident = make_ident(&format!("autocxx_box_{}", owner));
+        let drop_thunk_ident = make_ident(&format!("autocxx_drop_{}", owner));
+        let ctor_ident = make_ident(&format!("autocxx_make_{}", owner));
+
+        let method_sigs: Vec<TokenStream2> = methods
+            .iter()
+            .map(|m| {
+                let name = &m.name;
+                let inputs = &m.inputs;
+                let output = &m.output;
+                quote! { fn #name(&self #(, #inputs)*) #output; }
+            })
+            .collect();
+        let trait_item: Item = parse_quote! {
+            /// Implement this trait on a Rust type and pass an instance to
+            /// the generated boxing function to let the corresponding C++
+            /// abstract class call back into Rust through its vtable.
+            pub trait #trait_ident {
+                #(#method_sigs)*
+            }
+        };
+
+        let thunk_idents: Vec<_> = methods
+            .iter()
+            .map(|m| make_ident(&format!("autocxx_{}_{}_thunk", owner, m.name)))
+            .collect();
+        let thunk_items: Vec<Item> = methods
+            .iter()
+            .zip(&thunk_idents)
+            .map(|(m, thunk_ident)| {
+                let name = &m.name;
+                let inputs = &m.inputs;
+                let arg_names: Vec<_> = inputs
+                    .iter()
+                    .filter_map(|a| a.name.as_ref().map(|(id, _)| id.clone()))
+                    .collect();
+                let output = &m.output;
+                parse_quote! {
+                    #[no_mangle]
+                    pub unsafe extern "C" fn #thunk_ident(
+                        rust_self: *const ::std::os::raw::c_void
+                        #(, #inputs)*
+                    ) #output {
+                        let obj: &::std::boxed::Box<dyn #trait_ident> =
+                            &*(rust_self as *const ::std::boxed::Box<dyn #trait_ident>);
+                        obj.#name(#(#arg_names),*)
+                    }
+                }
+            })
+            .collect();
+
+        let box_fn_item: Item = parse_quote! {
+            /// Boxes up a Rust implementation of the matching trait so that the
+            /// generated C++ shim subclass can hold it as an opaque
+            /// pointer and dispatch virtual calls back into it.
+            pub fn #box_fn_ident<T: #trait_ident + 'static>(obj: T) -> *mut ::std::os::raw::c_void {
+                ::std::boxed::Box::into_raw(::std::boxed::Box::new(
+                    ::std::boxed::Box::new(obj) as ::std::boxed::Box<dyn #trait_ident>,
+                )) as *mut ::std::os::raw::c_void
+            }
+        };
+        let drop_thunk_item: Item = parse_quote! {
+            #[no_mangle]
+            pub unsafe extern "C" fn #drop_thunk_ident(rust_self: *mut ::std::os::raw::c_void) {
+                ::std::mem::drop(::std::boxed::Box::from_raw(
+                    rust_self as *mut ::std::boxed::Box<dyn #trait_ident>,
+                ));
+            }
+        };
+
+        let additional_cpp = Self::generate_vtable_shim_cpp(
+            &owner,
+            &shim_ident,
+            &methods,
+            &thunk_idents,
+            &drop_thunk_ident,
+            &ctor_ident,
+        );
+
+        // The constructor is a C++-side function, like the type
+        // declaration itself, so it needs the same namespace attribute.
+        let ctor_ns_attr = Self::namespace_attr(&tyname);
+        let ctor_decl_ts = quote! {
+            #ctor_ns_attr
+            fn #ctor_ident(rust_self: *mut ::std::os::raw::c_void) -> UniquePtr<#owner_ident>;
+        };
+
+        self.add_api(Api {
+            id: trait_ident,
+            ns: ns.clone(),
+            bridge_item: None,
+            extern_c_mod_item: Some(ForeignItem::Verbatim(ctor_decl_ts)),
+            global_items: std::iter::once(trait_item)
+                .chain(std::iter::once(box_fn_item))
+                .chain(std::iter::once(drop_thunk_item))
+                .chain(thunk_items)
+                .collect(),
+            additional_cpp: Some(additional_cpp),
+            deps: std::iter::once(tyname).collect(),
+            use_stmt: Use::Unused,
+            id_for_allowlist: None,
+            bindgen_mod_item: None,
+            impl_entry: None,
+        });
+    }
+
+    /// Best-effort mapping from a Rust bare-fn argument/return type back to
+    /// the C++ spelling bindgen originally generated it from. Pointers and
+    /// references recurse onto their pointee, since those are what
+    /// virtual method signatures are made of almost exclusively; plain
+    /// primitives and bound types are covered below.
+    fn rust_type_to_cpp(ty: &Type) -> String {
+        match ty {
+            Type::Ptr(p) => {
+                let qualifier = if p.const_token.is_some() { "const " } else { "" };
+                format!("{}{}*", qualifier, Self::rust_type_to_cpp(&p.elem))
+            }
+            Type::Reference(r) => {
+                let qualifier = if r.mutability.is_some() { "" } else { "const " };
+                format!("{}{}&", qualifier, Self::rust_type_to_cpp(&r.elem))
+            }
+            _ => Self::rust_type_to_cpp_leaf(ty),
+        }
+    }
+
+    fn rust_type_to_cpp_leaf(ty: &Type) -> String {
+        let text = quote!(#ty).to_string().replace(' ', "");
+        match text.as_str() {
+            "i8" => return "int8_t".to_string(),
+            "u8" => return "uint8_t".to_string(),
+            "i16" => return "int16_t".to_string(),
+            "u16" => return "uint16_t".to_string(),
+            "i32" => return "int32_t".to_string(),
+            "u32" => return "uint32_t".to_string(),
+            "i64" => return "int64_t".to_string(),
+            "u64" => return "uint64_t".to_string(),
+            "f32" => return "float".to_string(),
+            "f64" => return "double".to_string(),
+            "bool" => return "bool".to_string(),
+            "()" => return "void".to_string(),
+            _ => {}
+        }
+        // A bound type: bindgen's own name for it (its last path segment,
+        // dropping the `root::`/namespace prefix we don't need in the
+        // shim, which lives inside the same C++ namespace already) is the
+        // correct C++ spelling, except for `c_void` and the other
+        // `std::os::raw` aliases, which map to their C++ builtin types.
+        if let Type::Path(tp) = ty {
+            if let Some(seg) = tp.path.segments.last() {
+                return match seg.ident.to_string().as_str() {
+                    "c_void" => "void",
+                    "c_char" => "char",
+                    "c_schar" => "signed char",
+                    "c_uchar" => "unsigned char",
+                    "c_short" => "short",
+                    "c_ushort" => "unsigned short",
+                    "c_int" => "int",
+                    "c_uint" => "unsigned int",
+                    "c_long" => "long",
+                    "c_ulong" => "unsigned long",
+                    "c_longlong" => "long long",
+                    "c_ulonglong" => "unsigned long long",
+                    other => other,
+                }
+                .to_string();
+            }
+        }
+        text.trim_start_matches("::").replace("::", "_")
+    }
+
+    fn generate_vtable_shim_cpp(
+        owner: &str,
+        shim_ident: &str,
+        methods: &[VirtualMethodSig],
+        thunk_idents: &[Ident],
+        drop_thunk_ident: &Ident,
+        ctor_ident: &Ident,
+    ) -> String {
+        let mut overrides = String::new();
+        let mut thunk_decls = String::new();
+        for (method, thunk_ident) in methods.iter().zip(thunk_idents) {
+            let ret_cpp = match &method.output {
+                ReturnType::Default => "void".to_string(),
+                ReturnType::Type(_, ty) => Self::rust_type_to_cpp(ty),
+            };
+            let params: Vec<String> = method
+                .inputs
+                .iter()
+                .enumerate()
+                .map(|(i, a)| format!("{} arg{}", Self::rust_type_to_cpp(&a.ty), i))
+                .collect();
+            let args: Vec<String> = (0..method.inputs.len()).map(|i| format!("arg{}", i)).collect();
+            overrides += &format!(
+                "    {ret} {name}({params}) override {{\n        return {thunk}(rust_self_{maybe_comma}{args});\n    }}\n",
+                ret = ret_cpp,
+                name = method.name,
+                params = params.join(", "),
+                thunk = thunk_ident,
+                maybe_comma = if args.is_empty() { "" } else { ", " },
+                args = args.join(", "),
+            );
+            thunk_decls += &format!(
+                "extern \"C\" {ret} {thunk}(const void* rust_self{maybe_comma}{params});\n",
+                ret = ret_cpp,
+                thunk = thunk_ident,
+                maybe_comma = if params.is_empty() { "" } else { ", " },
+                params = params.join(", "),
+            );
+        }
+        format!(
+            "extern \"C\" void {drop_thunk}(void* rust_self);\n{thunk_decls}\nclass {shim} : public {owner} {{\npublic:\n    explicit {shim}(void* rust_self) : rust_self_(rust_self) {{}}\n    ~{shim}() override {{ {drop_thunk}(rust_self_); }}\n{overrides}private:\n    void* rust_self_;\n}};\n\nstd::unique_ptr<{owner}> {ctor}(void* rust_self) {{\n    return std::make_unique<{shim}>(rust_self);\n}}\n",
+            drop_thunk = drop_thunk_ident,
+            thunk_decls = thunk_decls,
+            shim = shim_ident,
+            owner = owner,
+            overrides = overrides,
+            ctor = ctor_ident,
+        )
+    }
+
     fn spot_forward_declaration(s: &Fields) -> bool {
         s.iter()
             .filter_map(|f| f.ident.as_ref())
             .any(|id| id == "_unused")
     }
 
+    /// The `#[namespace = ...]` attribute token stream for an item
+    /// declared inside a bridge's `extern "C++"` block, or nothing if the
+    /// type is in the root namespace.
+    fn namespace_attr(tyname: &TypeName) -> TokenStream2 {
+        if tyname.has_namespace() {
+            let ns_string = tyname
+                .ns_segment_iter()
+                .cloned()
+                .collect::<Vec<String>>()
+                .join("::");
+            quote! {
+                #[namespace = #ns_string]
+            }
+        } else {
+            TokenStream2::new()
+        }
+    }
+
     /// Record the Api for a type, e.g. enum or struct.
     /// Code generated includes the bindgen entry itself,
     /// various entries for the cxx::bridge to ensure cxx
     /// is aware of the type, and 'use' statements for the final
     /// output mod hierarchy. All are stored in the Api which
     /// this adds.
+    /// The `#[namespace = ...]` attribute token stream for an item
+    /// declared inside a bridge's `extern "C++"` block, or nothing if the
+    /// type is in the root namespace.
+    fn namespace_attr(tyname: &TypeName) -> TokenStream2 {
+        if tyname.has_namespace() {
+            let ns_string = tyname
+                .ns_segment_iter()
+                .cloned()
+                .collect::<Vec<String>>()
+                .join("::");
+            quote! {
+                #[namespace = #ns_string]
+            }
+        } else {
+            TokenStream2::new()
+        }
+    }
+
+    /// Returns `false` if the type is on the blocklist, in which case no
+    /// `Api` was emitted for it at all and callers must not generate
+    /// anything else (e.g. a vtable trampoline) that refers to it.
     fn generate_type(
         &mut self,
         tyname: TypeName,
         type_nature: TypeKind,
         deps: HashSet<TypeName>,
         bindgen_mod_item: Option<Item>,
-    ) {
+        impl_entry: Option<Item>,
+    ) -> bool {
         let final_ident = make_ident(tyname.get_final_ident());
         let kind_item = match type_nature {
             TypeKind::POD => "Trivial",
@@ -275,21 +1274,10 @@ impl<'a> ParseBindgen<'a> {
         };
         let kind_item = make_ident(kind_item);
         if self.type_database.is_on_blocklist(&tyname) {
-            return;
+            return false;
         }
         let tynamestring = tyname.to_cpp_name();
-        let mut for_extern_c_ts = if tyname.has_namespace() {
-            let ns_string = tyname
-                .ns_segment_iter()
-                .cloned()
-                .collect::<Vec<String>>()
-                .join("::");
-            quote! {
-                #[namespace = #ns_string]
-            }
-        } else {
-            TokenStream2::new()
-        };
+        let mut for_extern_c_ts = Self::namespace_attr(&tyname);
 
         let mut fulltypath: Vec<_> = ["bindgen", "root"].iter().map(|x| make_ident(x)).collect();
         for_extern_c_ts.extend(quote! {
@@ -328,10 +1316,11 @@ impl<'a> ParseBindgen<'a> {
             deps,
             id_for_allowlist: None,
             bindgen_mod_item,
-            impl_entry: None,
+            impl_entry,
         };
         self.add_api(api);
         self.type_converter.push(tyname);
+        true
     }
 }
 